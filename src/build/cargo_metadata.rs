@@ -0,0 +1,94 @@
+// Copyright 2016 The Rustw Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Minimal support for shelling out to `cargo metadata` so we can discover
+//! where a workspace keeps its build artefacts (and which crates are
+//! workspace members) instead of assuming `target/debug`.
+
+use serde_json;
+
+use std::path::PathBuf;
+use std::process::Command;
+
+#[derive(Deserialize, Debug)]
+struct Metadata {
+    packages: Vec<Package>,
+    workspace_members: Vec<String>,
+    target_directory: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Package {
+    name: String,
+    id: String,
+}
+
+/// The result of running `cargo metadata`: the workspace's target directory
+/// and the names of every workspace member crate.
+pub struct WorkspaceInfo {
+    pub target_directory: PathBuf,
+    pub member_names: Vec<String>,
+}
+
+/// Shells out to `cargo metadata --format-version 1` in the current
+/// directory and parses the result.
+pub fn workspace_info() -> Result<WorkspaceInfo, String> {
+    let output = Command::new("cargo")
+        .args(&["metadata", "--format-version", "1", "--no-deps"])
+        .output()
+        .map_err(|e| format!("could not run `cargo metadata`: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!("`cargo metadata` exited with {:?}", output.status.code()));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_metadata(&stdout)
+}
+
+// Split out from `workspace_info` so the parsing/filtering can be unit
+// tested without actually shelling out to `cargo`.
+fn parse_metadata(json: &str) -> Result<WorkspaceInfo, String> {
+    let metadata: Metadata = serde_json::from_str(json)
+        .map_err(|e| format!("could not parse `cargo metadata` output: {}", e))?;
+
+    let member_names = metadata.packages.into_iter()
+        .filter(|p| metadata.workspace_members.contains(&p.id))
+        .map(|p| p.name)
+        .collect();
+
+    Ok(WorkspaceInfo {
+        target_directory: PathBuf::from(metadata.target_directory),
+        member_names: member_names,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn member_names_excludes_non_member_packages() {
+        let json = r#"{
+            "packages": [
+                {"name": "foo", "id": "foo 0.1.0 (path+file:///ws/foo)"},
+                {"name": "bar", "id": "bar 0.1.0 (path+file:///ws/bar)"},
+                {"name": "some-dep", "id": "some-dep 1.2.3 (registry+https://github.com/rust-lang/crates.io-index)"}
+            ],
+            "workspace_members": [
+                "foo 0.1.0 (path+file:///ws/foo)",
+                "bar 0.1.0 (path+file:///ws/bar)"
+            ],
+            "target_directory": "/ws/target"
+        }"#;
+
+        let info = parse_metadata(json).unwrap();
+        assert_eq!(info.target_directory, PathBuf::from("/ws/target"));
+        assert_eq!(info.member_names, vec!["foo".to_owned(), "bar".to_owned()]);
+    }
+}
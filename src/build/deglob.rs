@@ -0,0 +1,192 @@
+// Copyright 2016 The Rustw Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Turns a `use foo::*;` glob import into an explicit `use foo::{A, B};`
+//! list, based on which of `foo`'s items the file actually references.
+
+use super::{Analysis, Import, ImportKind};
+use super::diagnostics::Edit;
+use super::index::AnalysisIndex;
+
+use std::collections::BTreeSet;
+
+/// Computes the deglob edit for `import`, which must be a `GlobUse` import
+/// found in `analysis`. Returns `None` if `import` isn't a glob, or if its
+/// target module couldn't be resolved in `index`.
+///
+/// The edit either replaces the whole `use foo::*;` with an explicit list
+/// (sorted alphabetically, skipping any name already imported explicitly
+/// elsewhere in the file), or, if nothing from the glob is used, deletes it.
+pub fn deglob(import: &Import, analysis: &Analysis, index: &AnalysisIndex) -> Option<Edit> {
+    match import.kind {
+        ImportKind::GlobUse => {}
+        _ => return None,
+    }
+
+    let module = index.def_by_id(&import.id)?;
+    let prefix = format!("{}::", module.qualname);
+
+    let already_explicit: BTreeSet<_> = analysis.imports.iter()
+        .filter(|i| i.span.file_name == import.span.file_name)
+        .filter(|i| match i.kind { ImportKind::Use => true, _ => false })
+        .map(|i| i.name.clone())
+        .collect();
+
+    let used_names: BTreeSet<_> = analysis.refs.iter()
+        .filter(|r| r.span.file_name == import.span.file_name)
+        .filter_map(|r| index.def_by_id(&r.ref_id))
+        .filter(|d| d.qualname.starts_with(&prefix) && !d.qualname[prefix.len()..].contains("::"))
+        .map(|d| d.name.clone())
+        .filter(|name| !already_explicit.contains(name))
+        .collect();
+
+    let path = if import.value.ends_with("::*") {
+        &import.value[..import.value.len() - 3]
+    } else {
+        &import.value[..]
+    };
+
+    let replacement = if used_names.is_empty() {
+        String::new()
+    } else {
+        let names: Vec<_> = used_names.into_iter().collect();
+        format!("use {}::{{{}}};", path, names.join(", "))
+    };
+
+    Some(Edit {
+        file_name: import.span.file_name.clone(),
+        byte_start: import.span.byte_start,
+        byte_end: import.span.byte_end,
+        replacement: replacement,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::{CompilerId, Def, DefKind, Ref, RefKind, SpanData};
+
+    fn span(byte_start: u32, byte_end: u32) -> SpanData {
+        SpanData {
+            file_name: "src/lib.rs".to_owned(),
+            byte_start: byte_start,
+            byte_end: byte_end,
+            line_start: 1,
+            line_end: 1,
+            column_start: 1,
+            column_end: 1,
+        }
+    }
+
+    fn module_def(index: u32, qualname: &str) -> Def {
+        Def {
+            kind: DefKind::Mod,
+            id: CompilerId { krate: 0, index: index },
+            span: span(0, 0),
+            name: qualname.rsplit("::").next().unwrap().to_owned(),
+            qualname: qualname.to_owned(),
+            value: String::new(),
+        }
+    }
+
+    fn item_def(index: u32, module_qualname: &str, name: &str) -> Def {
+        Def {
+            kind: DefKind::Function,
+            id: CompilerId { krate: 0, index: index },
+            span: span(0, 0),
+            name: name.to_owned(),
+            qualname: format!("{}::{}", module_qualname, name),
+            value: String::new(),
+        }
+    }
+
+    fn ref_to(def: &Def) -> Ref {
+        Ref {
+            kind: RefKind::Function,
+            span: span(0, 0),
+            ref_id: def.id.clone(),
+        }
+    }
+
+    fn glob_import(module: &Def) -> Import {
+        Import {
+            kind: ImportKind::GlobUse,
+            id: module.id.clone(),
+            span: span(100, 120),
+            name: "*".to_owned(),
+            value: format!("{}::*", module.qualname),
+        }
+    }
+
+    #[test]
+    fn replaces_glob_with_explicit_list_of_used_names() {
+        let module = module_def(1, "krate::mymod");
+        let a = item_def(2, "krate::mymod", "A");
+        let b = item_def(3, "krate::mymod", "B");
+        let import = glob_import(&module);
+
+        let analyses = vec![Analysis {
+            prelude: None,
+            imports: vec![glob_import(&module)],
+            defs: vec![module, a.clone(), b.clone()],
+            refs: vec![ref_to(&a), ref_to(&b)],
+            macro_refs: vec![],
+        }];
+        let index = AnalysisIndex::new(&analyses);
+
+        let edit = deglob(&import, &analyses[0], &index).unwrap();
+        assert_eq!(edit.byte_start, 100);
+        assert_eq!(edit.byte_end, 120);
+        assert_eq!(edit.replacement, "use krate::mymod::{A, B};");
+    }
+
+    #[test]
+    fn deletes_glob_import_with_no_used_names() {
+        let module = module_def(1, "krate::mymod");
+        let import = glob_import(&module);
+
+        let analyses = vec![Analysis {
+            prelude: None,
+            imports: vec![glob_import(&module)],
+            defs: vec![module],
+            refs: vec![],
+            macro_refs: vec![],
+        }];
+        let index = AnalysisIndex::new(&analyses);
+
+        let edit = deglob(&import, &analyses[0], &index).unwrap();
+        assert_eq!(edit.replacement, "");
+    }
+
+    #[test]
+    fn skips_names_already_imported_explicitly() {
+        let module = module_def(1, "krate::mymod");
+        let a = item_def(2, "krate::mymod", "A");
+        let b = item_def(3, "krate::mymod", "B");
+        let import = glob_import(&module);
+        let explicit_a = Import {
+            kind: ImportKind::Use,
+            id: a.id.clone(),
+            span: span(0, 10),
+            name: "A".to_owned(),
+            value: "krate::mymod::A".to_owned(),
+        };
+
+        let analyses = vec![Analysis {
+            prelude: None,
+            imports: vec![glob_import(&module), explicit_a],
+            defs: vec![module, a.clone(), b.clone()],
+            refs: vec![ref_to(&a), ref_to(&b)],
+            macro_refs: vec![],
+        }];
+        let index = AnalysisIndex::new(&analyses);
+
+        let edit = deglob(&import, &analyses[0], &index).unwrap();
+        assert_eq!(edit.replacement, "use krate::mymod::{B};");
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2016 The Rustw Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Structured parsing of rustc's `--error-format json` output, so the
+//! frontend can render diagnostics (and offer machine-applicable fixes)
+//! instead of dumping raw `stderr`.
+
+use serde;
+use serde::Deserialize;
+use serde_json;
+
+/// A single rustc diagnostic, or one of its child `note`/`help` entries.
+#[derive(Deserialize, Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub level: String,
+    pub code: Option<DiagnosticCode>,
+    pub spans: Vec<DiagnosticSpan>,
+    pub children: Vec<Diagnostic>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiagnosticCode {
+    pub code: String,
+    pub explanation: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiagnosticSpan {
+    pub file_name: String,
+    pub byte_start: u32,
+    pub byte_end: u32,
+    pub line_start: usize,
+    pub line_end: usize,
+    pub column_start: usize,
+    pub column_end: usize,
+    // Not every rustc version emits this field (and cargo's own fixtures
+    // predate it), so default to `false` rather than dropping the whole
+    // diagnostic when it's missing.
+    #[serde(default)]
+    pub is_primary: bool,
+    pub label: Option<String>,
+    pub suggested_replacement: Option<String>,
+    pub suggestion_applicability: Option<Applicability>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Applicability {
+    MachineApplicable,
+    HasPlaceholders,
+    MaybeIncorrect,
+    Unspecified,
+}
+
+// Custom impl to read rustc_serialize's format.
+impl Deserialize for Applicability {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Applicability, D::Error>
+        where D: serde::Deserializer,
+    {
+        let s = String::deserialize(deserializer)?;
+        match &*s {
+            "MachineApplicable" => Ok(Applicability::MachineApplicable),
+            "HasPlaceholders" => Ok(Applicability::HasPlaceholders),
+            "MaybeIncorrect" => Ok(Applicability::MaybeIncorrect),
+            "Unspecified" => Ok(Applicability::Unspecified),
+            _ => Err(serde::de::Error::custom("unexpected applicability")),
+        }
+    }
+}
+
+/// A single, concrete edit to a source file, derived from a diagnostic's
+/// machine-applicable suggestion.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Edit {
+    pub file_name: String,
+    pub byte_start: u32,
+    pub byte_end: u32,
+    pub replacement: String,
+}
+
+impl Diagnostic {
+    // Collects edits from this diagnostic and its children into `edits`.
+    fn machine_applicable_edits(&self, edits: &mut Vec<Edit>) {
+        for span in &self.spans {
+            if span.suggestion_applicability == Some(Applicability::MachineApplicable) {
+                if let Some(ref replacement) = span.suggested_replacement {
+                    edits.push(Edit {
+                        file_name: span.file_name.clone(),
+                        byte_start: span.byte_start,
+                        byte_end: span.byte_end,
+                        replacement: replacement.clone(),
+                    });
+                }
+            }
+        }
+
+        for child in &self.children {
+            child.machine_applicable_edits(edits);
+        }
+    }
+}
+
+/// Parses rustc's `--error-format json` output. cargo interleaves this with
+/// plain text on `stdout` (and, in older versions, on `stderr` too), so lines
+/// that don't deserialize as a `Diagnostic` are skipped rather than treated
+/// as an error.
+pub fn parse_diagnostics(stderr: &str) -> Vec<Diagnostic> {
+    stderr.lines()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .collect()
+}
+
+/// Flattens the machine-applicable suggestions of `diagnostics` (and their
+/// children) into a list of edits a frontend can apply directly.
+pub fn machine_applicable_edits(diagnostics: &[Diagnostic]) -> Vec<Edit> {
+    let mut edits = vec![];
+    for d in diagnostics {
+        d.machine_applicable_edits(&mut edits);
+    }
+    edits
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // rustc doesn't always send `is_primary` (and `BuildResult::test_result`'s
+    // fixture doesn't either), so a line missing it must still parse instead
+    // of being silently dropped by `parse_diagnostics`' `filter_map`.
+    #[test]
+    fn is_primary_defaults_when_absent() {
+        let line = r#"{"message":"unused variable: `x`","code":null,"level":"warning","spans":[{"file_name":"src/lib.rs","byte_start":1,"byte_end":2,"line_start":1,"line_end":1,"column_start":1,"column_end":2,"label":null,"suggested_replacement":null,"suggestion_applicability":null}],"children":[]}"#;
+        let diagnostics = parse_diagnostics(line);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].spans[0].is_primary, false);
+    }
+
+    #[test]
+    fn machine_applicable_edits_extracted_from_children() {
+        let line = r#"{"message":"unused import","code":null,"level":"warning","spans":[],"children":[{"message":"remove the import","code":null,"level":"help","spans":[{"file_name":"src/lib.rs","byte_start":10,"byte_end":20,"line_start":2,"line_end":2,"column_start":1,"column_end":10,"is_primary":true,"label":null,"suggested_replacement":"","suggestion_applicability":"MachineApplicable"}],"children":[]}]}"#;
+        let diagnostics = parse_diagnostics(line);
+        let edits = machine_applicable_edits(&diagnostics);
+        assert_eq!(edits, vec![Edit {
+            file_name: "src/lib.rs".to_owned(),
+            byte_start: 10,
+            byte_end: 20,
+            replacement: "".to_owned(),
+        }]);
+    }
+}
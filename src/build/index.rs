@@ -0,0 +1,197 @@
+// Copyright 2016 The Rustw Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Indexes save-analysis data for "go to definition" and "type on hover":
+//! given a file and a byte offset, find the `Ref` covering it and follow it
+//! to its `Def`.
+
+use super::{Analysis, CompilerId, Def};
+
+use std::collections::HashMap;
+
+/// An index over a set of `Analysis` values, built once after
+/// `Builder::read_analysis` and then queried by the frontend.
+pub struct AnalysisIndex {
+    defs: HashMap<CompilerId, Def>,
+    // Per-file, sorted by `byte_start` (and, for ties, by span length). A
+    // binary search narrows a lookup to every span that could possibly
+    // cover a given offset; picking the innermost of those is still a scan
+    // of that prefix (refs nest, e.g. a method call and its receiver, so
+    // there's no getting away with the single nearest candidate).
+    refs: HashMap<String, Vec<RefSpan>>,
+}
+
+struct RefSpan {
+    byte_start: u32,
+    byte_end: u32,
+    ref_id: CompilerId,
+}
+
+impl AnalysisIndex {
+    pub fn new(analyses: &[Analysis]) -> AnalysisIndex {
+        let mut defs = HashMap::new();
+        let mut refs: HashMap<String, Vec<RefSpan>> = HashMap::new();
+
+        for analysis in analyses {
+            for def in &analysis.defs {
+                defs.insert(def.id.clone(), def.clone());
+            }
+
+            for r in &analysis.refs {
+                refs.entry(r.span.file_name.clone())
+                    .or_insert_with(Vec::new)
+                    .push(RefSpan {
+                        byte_start: r.span.byte_start,
+                        byte_end: r.span.byte_end,
+                        ref_id: r.ref_id.clone(),
+                    });
+            }
+        }
+
+        for entries in refs.values_mut() {
+            entries.sort_by(|a, b| {
+                a.byte_start.cmp(&b.byte_start)
+                    .then((a.byte_end - a.byte_start).cmp(&(b.byte_end - b.byte_start)))
+            });
+        }
+
+        AnalysisIndex {
+            defs: defs,
+            refs: refs,
+        }
+    }
+
+    /// Finds the definition of whatever is referenced at `byte_offset` in
+    /// `file`, if anything is. `Def::span` is the jump-to-def target;
+    /// `Def::value`/`Def::qualname` are the hover text.
+    pub fn def_at(&self, file: &str, byte_offset: u32) -> Option<&Def> {
+        let entries = self.refs.get(file)?;
+        let covering = AnalysisIndex::innermost_covering(entries, byte_offset)?;
+        self.defs.get(&covering.ref_id)
+    }
+
+    /// Looks up a definition directly by its compiler id, e.g. to resolve
+    /// an `Import`'s `id` to the module/item it imports.
+    pub fn def_by_id(&self, id: &CompilerId) -> Option<&Def> {
+        self.defs.get(id)
+    }
+
+    // Finds the smallest span covering `byte_offset`. Spans are sorted by
+    // `byte_start`, so a binary search finds the boundary past which no
+    // entry can possibly start at or before `byte_offset` - that part is
+    // O(log n). But an outer span (e.g. a method call) can start well
+    // before an inner one (its receiver) and still enclose `byte_offset`
+    // after the inner span has ended, so every entry up to that boundary
+    // is a candidate, not just the ones sharing the nearest `byte_start`:
+    // picking the innermost one is an O(n) scan of that prefix in the
+    // worst case (e.g. an offset inside deeply/widely nested spans), not
+    // O(log n) overall. Files are small enough in practice that this
+    // hasn't mattered; a running "max `byte_end` seen so far" alongside
+    // the sort, or a proper interval tree, would be the way to make the
+    // whole lookup logarithmic if it ever does.
+    fn innermost_covering(entries: &[RefSpan], byte_offset: u32) -> Option<&RefSpan> {
+        let end = match entries.binary_search_by(|e| e.byte_start.cmp(&byte_offset)) {
+            Ok(i) => {
+                let mut i = i;
+                while i < entries.len() && entries[i].byte_start == byte_offset {
+                    i += 1;
+                }
+                i
+            }
+            Err(i) => i,
+        };
+
+        entries[..end].iter()
+            .filter(|e| e.byte_end > byte_offset)
+            .min_by_key(|e| e.byte_end - e.byte_start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn span(byte_start: u32, byte_end: u32, krate: u32) -> RefSpan {
+        RefSpan {
+            byte_start: byte_start,
+            byte_end: byte_end,
+            ref_id: CompilerId { krate: krate, index: 0 },
+        }
+    }
+
+    // An outer span (e.g. a method call) that starts before, and ends
+    // after, an inner one (its receiver) must still be found for offsets
+    // past the inner span's end.
+    #[test]
+    fn finds_outer_span_past_a_nested_inner_spans_end() {
+        let entries = vec![span(0, 100, 1), span(4, 7, 2)];
+
+        let found = AnalysisIndex::innermost_covering(&entries, 50).unwrap();
+        assert_eq!(found.ref_id, CompilerId { krate: 1, index: 0 });
+    }
+
+    #[test]
+    fn prefers_innermost_of_several_nested_spans() {
+        let entries = vec![span(0, 100, 1), span(4, 50, 2), span(4, 7, 3)];
+
+        let found = AnalysisIndex::innermost_covering(&entries, 5).unwrap();
+        assert_eq!(found.ref_id, CompilerId { krate: 3, index: 0 });
+    }
+
+    #[test]
+    fn no_span_covers_the_offset() {
+        let entries = vec![span(0, 10, 1), span(20, 30, 2)];
+
+        assert!(AnalysisIndex::innermost_covering(&entries, 15).is_none());
+    }
+
+    // `def_at` is the actual public entry point the frontend calls for
+    // go-to-definition/hover; exercise it end-to-end rather than only the
+    // private `innermost_covering` helper.
+    #[test]
+    fn def_at_resolves_a_ref_to_its_def() {
+        use super::super::{DefKind, Ref, RefKind, SpanData};
+
+        let span_data = |byte_start, byte_end| SpanData {
+            file_name: "src/lib.rs".to_owned(),
+            byte_start: byte_start,
+            byte_end: byte_end,
+            line_start: 1,
+            line_end: 1,
+            column_start: 1,
+            column_end: 1,
+        };
+
+        let def_id = CompilerId { krate: 0, index: 1 };
+        let analyses = vec![Analysis {
+            prelude: None,
+            imports: vec![],
+            defs: vec![Def {
+                kind: DefKind::Function,
+                id: def_id.clone(),
+                span: span_data(0, 3),
+                name: "foo".to_owned(),
+                qualname: "krate::foo".to_owned(),
+                value: String::new(),
+            }],
+            refs: vec![Ref {
+                kind: RefKind::Function,
+                span: span_data(10, 13),
+                ref_id: def_id,
+            }],
+            macro_refs: vec![],
+        }];
+        let index = AnalysisIndex::new(&analyses);
+
+        let def = index.def_at("src/lib.rs", 11).unwrap();
+        assert_eq!(def.name, "foo");
+
+        assert!(index.def_at("src/lib.rs", 20).is_none());
+        assert!(index.def_at("src/other.rs", 11).is_none());
+    }
+}
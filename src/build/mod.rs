@@ -6,7 +6,14 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+pub mod cargo_metadata;
+pub mod deglob;
+pub mod diagnostics;
 pub mod errors;
+pub mod index;
+
+pub use self::diagnostics::{Diagnostic, Edit};
+pub use self::index::AnalysisIndex;
 
 use config::Config;
 use file_cache::{DirectoryListing, ListingKind};
@@ -15,14 +22,26 @@ use serde;
 use serde::Deserialize;
 use serde_json;
 
-use std::process::{Command, Output};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
-use std::path::Path;
 use std::fs::File;
-use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct Builder {
     config: Arc<Config>,
+    // Directories to scan for `save-analysis/*.json` files, discovered (where
+    // possible) via `cargo metadata` so that release builds, a custom
+    // `CARGO_TARGET_DIR`, and multi-crate workspaces are all handled.
+    analysis_roots: Vec<PathBuf>,
+    // Names of this workspace's member crates, from `cargo metadata`.
+    // Empty if `cargo metadata` failed, in which case `read_analysis`
+    // can't tell members from stray deps and skips the filtering.
+    member_names: Vec<String>,
 }
 
 pub struct BuildResult {
@@ -30,27 +49,108 @@ pub struct BuildResult {
     pub stdout: String,
     pub stderr: String,
     pub analysis: Vec<Analysis>,
+    pub build_duration: Duration,
 }
 
-// TODO
-// In file_cache, add our own stuff (deglob/type on hover)
-
-
+/// A piece of progress from a build started with `Builder::build_async`,
+/// sent as soon as it's available rather than buffered until the build
+/// finishes.
+pub enum BuildProgress {
+    /// A line of cargo's own stdout noise (e.g. "   Compiling foo v0.1.0").
+    Stdout(String),
+    /// A diagnostic, parsed as soon as its line of `stderr` arrives.
+    Diagnostic(Diagnostic),
+    /// The build finished; this is the last message sent.
+    Done(BuildResult),
+    /// The build could not even be started (bad build command, or the
+    /// process failed to spawn).
+    Failed,
+}
 
 impl Builder {
     pub fn from_config(config: Arc<Config>) -> Builder {
+        let (analysis_roots, member_names) = Builder::discover_workspace(&config);
         Builder {
             config: config,
+            analysis_roots: analysis_roots,
+            member_names: member_names,
         }
     }
 
+    // Works out where to look for save-analysis data, and which crates are
+    // workspace members. We prefer asking `cargo metadata` (this copes with
+    // release builds, a custom `CARGO_TARGET_DIR`, and workspaces with
+    // several members), falling back to the old single-crate
+    // `target/<profile>` layout (and no member filtering) if that fails, e.g.,
+    // because the build command isn't Cargo at all.
+    fn discover_workspace(config: &Config) -> (Vec<PathBuf>, Vec<String>) {
+        let profile = if config.build_command.contains("--release") {
+            "release"
+        } else {
+            "debug"
+        };
+
+        let (target_dir, member_names) = match cargo_metadata::workspace_info() {
+            Ok(info) => (info.target_directory, info.member_names),
+            Err(e) => {
+                warn!("`cargo metadata` failed ({}), falling back to `target/{}`", e, profile);
+                (PathBuf::from("target"), vec![])
+            }
+        };
+
+        let roots = vec![
+            target_dir.join(profile).join("save-analysis"),
+            target_dir.join(profile).join("deps").join("save-analysis"),
+        ];
+
+        (roots, member_names)
+    }
+
+    /// Runs the build and blocks until it's done. Internally this drives
+    /// the same streaming machinery as `build_async`; callers who want
+    /// progress as it happens should use that instead.
     pub fn build(&self) -> Result<BuildResult, ()> {
+        for progress in self.build_async() {
+            match progress {
+                BuildProgress::Done(result) => return Ok(result),
+                BuildProgress::Failed => return Err(()),
+                BuildProgress::Stdout(_) | BuildProgress::Diagnostic(_) => {}
+            }
+        }
+
+        Err(())
+    }
+
+    /// Starts the build on a background thread and returns a channel of
+    /// `BuildProgress`, so a long build reports diagnostics as they're
+    /// emitted rather than only once the whole thing has finished.
+    pub fn build_async(&self) -> Receiver<BuildProgress> {
+        let (tx, rx) = mpsc::channel();
+
+        let config = self.config.clone();
+        let analysis_roots = self.analysis_roots.clone();
+        let member_names = self.member_names.clone();
+        thread::spawn(move || {
+            let builder = Builder {
+                config: config,
+                analysis_roots: analysis_roots,
+                member_names: member_names,
+            };
+            builder.run(&tx);
+        });
+
+        rx
+    }
+
+    // Does the actual work of `build_async`, on whatever thread it's called from.
+    fn run(&self, tx: &Sender<BuildProgress>) {
         let mut build_split = self.config.build_command.split(' ');
         let mut cmd = if let Some(cmd) = build_split.next() {
             Command::new(cmd)
         } else {
-            println!("build error - no build command");
-            return Err(());
+            error!("build error - no build command");
+            let _ = tx.send(BuildProgress::Failed);
+            return;
         };
 
         for arg in build_split.next() {
@@ -62,29 +162,72 @@ impl Builder {
             flags.push_str(" -Zsave-analysis");
         }
         cmd.env("RUSTFLAGS", &flags);
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
 
-        // TODO execute async
+        info!("building...");
+        let start = Instant::now();
 
-        // TODO record compile time
-
-        // TODO log, not println
-        println!("building...");
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("error: `{}`; command: `{}`", e, self.config.build_command);
+                let _ = tx.send(BuildProgress::Failed);
+                return;
+            }
+        };
 
-        let output = match cmd.output() {
-            Ok(o) => {
-                println!("done");
-                o
+        // Read stdout and stderr concurrently on their own threads; cargo
+        // can write enough to either pipe to deadlock a single-threaded
+        // reader against a child that's blocked writing to the other one.
+        let stdout = child.stdout.take().expect("child was spawned with piped stdout");
+        let stdout_tx = tx.clone();
+        let stdout_thread = thread::spawn(move || {
+            let mut lines = vec![];
+            for line in BufReader::new(stdout).lines().filter_map(|l| l.ok()) {
+                let _ = stdout_tx.send(BuildProgress::Stdout(line.clone()));
+                lines.push(line);
             }
+            lines.join("\n")
+        });
+
+        let stderr = child.stderr.take().expect("child was spawned with piped stderr");
+        let stderr_tx = tx.clone();
+        let stderr_thread = thread::spawn(move || {
+            let mut lines = vec![];
+            for line in BufReader::new(stderr).lines().filter_map(|l| l.ok()) {
+                if let Ok(diagnostic) = serde_json::from_str(&line) {
+                    let _ = stderr_tx.send(BuildProgress::Diagnostic(diagnostic));
+                }
+                lines.push(line);
+            }
+            lines.join("\n")
+        });
+
+        let status = child.wait();
+        let stdout = stdout_thread.join().unwrap_or_else(|_| String::new());
+        let stderr = stderr_thread.join().unwrap_or_else(|_| String::new());
+        let build_duration = start.elapsed();
+
+        let status = match status {
+            Ok(status) => status.code(),
             Err(e) => {
-                // TODO could handle this error more nicely.
-                println!("error: `{}`; command: `{}`", e, self.config.build_command);
-                return Err(());
+                error!("error waiting on build: `{}`", e);
+                None
             }
         };
 
-        let result = BuildResult::from_process_output(output, self.read_analysis());
+        info!("done in {:?}", build_duration);
+
+        let result = BuildResult {
+            status: status,
+            stdout: stdout,
+            stderr: stderr,
+            analysis: self.read_analysis(),
+            build_duration: build_duration,
+        };
 
-        Ok(result)
+        let _ = tx.send(BuildProgress::Done(result));
     }
 
     // TODO just save the strings here, parse JSON in reprocess.rs
@@ -95,11 +238,14 @@ impl Builder {
             return result;
         }
 
-        // TODO shouldn't hard-code this path, it's cargo-specific
-        // TODO deps path allows to break out of sandbox - is that Ok?
-        let paths = &[&Path::new("target/debug/save-analysis"), &Path::new("target/debug/deps/save-analysis")];
+        // A crate can show up under more than one root (e.g., a workspace
+        // member's analysis is read once from `save-analysis` and again, for
+        // a dependent crate's build, via `deps/save-analysis`), so dedupe on
+        // the crate's own identity rather than on file path.
+        let mut seen_crates = HashSet::new();
 
-        for p in paths {
+        // TODO deps path allows to break out of sandbox - is that Ok?
+        for p in &self.analysis_roots {
             let listing = match DirectoryListing::from_path(p) {
                 Ok(l) => l,
                 Err(_) => { continue; },
@@ -108,15 +254,41 @@ impl Builder {
                 if l.kind == ListingKind::File {
                     let mut path = p.to_path_buf();
                     path.push(&l.name);
-                    println!("reading {:?}", path);
+                    debug!("reading {:?}", path);
                     // TODO unwraps
                     let mut file = File::open(&path).unwrap();
                     let mut buf = String::new();
                     file.read_to_string(&mut buf).unwrap();
-                    match serde_json::from_str(&buf) {
-                        Ok(a) => result.push(a),
-                        Err(e) => println!("{}", e),
+                    let analysis: Analysis = match serde_json::from_str(&buf) {
+                        Ok(a) => a,
+                        Err(e) => { warn!("{}", e); continue; },
+                    };
+
+                    let crate_name = analysis.prelude.as_ref().map(|p| p.crate_name.clone());
+
+                    // Skip save-analysis data for crates we know aren't
+                    // workspace members (e.g. a path dependency that writes
+                    // into the same target directory). If `cargo metadata`
+                    // failed, `member_names` is empty and we can't tell, so
+                    // fall back to keeping everything.
+                    // `member_names` are package names, which may contain
+                    // '-' where the crate name has '_'.
+                    if let Some(ref crate_name) = crate_name {
+                        if !self.member_names.is_empty() &&
+                           !self.member_names.iter().any(|m| m.replace("-", "_") == *crate_name) {
+                            continue;
+                        }
                     }
+
+                    let crate_id = analysis.prelude.as_ref()
+                        .map(|p| (p.crate_name.clone(), p.crate_root.clone()));
+                    if let Some(crate_id) = crate_id {
+                        if !seen_crates.insert(crate_id) {
+                            continue;
+                        }
+                    }
+
+                    result.push(analysis);
                 }
             }
         }
@@ -134,7 +306,7 @@ pub struct Analysis {
     pub macro_refs: Vec<MacroRef>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CompilerId {
     pub krate: u32,
     pub index: u32,
@@ -155,7 +327,7 @@ pub struct ExternalCrateData {
     pub file_name: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Def {
     pub kind: DefKind,
     pub id: CompilerId,
@@ -165,17 +337,28 @@ pub struct Def {
     pub value: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DefKind {
     Enum,
     Tuple,
     Struct,
+    StructVariant,
+    TupleVariant,
     Trait,
     Function,
+    Method,
     Macro,
     Mod,
     Type,
+    Local,
+    Static,
+    Const,
+    Field,
     Variable,
+    // A kind added to the save-analysis schema since this was written. We'd
+    // rather degrade gracefully than lose an entire crate's analysis to one
+    // unrecognised def kind.
+    Unknown(String),
 }
 
 // Custom impl to read rustc_serialize's format.
@@ -184,18 +367,25 @@ impl Deserialize for DefKind {
         where D: serde::Deserializer,
     {
         let s = String::deserialize(deserializer)?;
-        match &*s {
-            "Enum" => Ok(DefKind::Enum),
-            "Tuple" => Ok(DefKind::Tuple),
-            "Struct" => Ok(DefKind::Struct),
-            "Trait" => Ok(DefKind::Trait),
-            "Function" => Ok(DefKind::Function),
-            "Macro" => Ok(DefKind::Macro),
-            "Mod" => Ok(DefKind::Mod),
-            "Type" => Ok(DefKind::Type),
-            "Variable" => Ok(DefKind::Variable),
-            _ => Err(serde::de::Error::custom("unexpected def kind")),
-        }
+        Ok(match &*s {
+            "Enum" => DefKind::Enum,
+            "Tuple" => DefKind::Tuple,
+            "Struct" => DefKind::Struct,
+            "StructVariant" => DefKind::StructVariant,
+            "TupleVariant" => DefKind::TupleVariant,
+            "Trait" => DefKind::Trait,
+            "Function" => DefKind::Function,
+            "Method" => DefKind::Method,
+            "Macro" => DefKind::Macro,
+            "Mod" => DefKind::Mod,
+            "Type" => DefKind::Type,
+            "Local" => DefKind::Local,
+            "Static" => DefKind::Static,
+            "Const" => DefKind::Const,
+            "Field" => DefKind::Field,
+            "Variable" => DefKind::Variable,
+            _ => DefKind::Unknown(s),
+        })
     }
 }
 
@@ -212,6 +402,8 @@ pub enum RefKind {
     Mod,
     Type,
     Variable,
+    // See `DefKind::Unknown`.
+    Unknown(String),
 }
 
 // Custom impl to read rustc_serialize's format.
@@ -220,13 +412,13 @@ impl Deserialize for RefKind {
         where D: serde::Deserializer,
     {
         let s = String::deserialize(deserializer)?;
-        match &*s {
-            "Function" => Ok(RefKind::Function),
-            "Mod" => Ok(RefKind::Mod),
-            "Type" => Ok(RefKind::Type),
-            "Variable" => Ok(RefKind::Variable),
-            _ => Err(serde::de::Error::custom("unexpected ref kind")),
-        }
+        Ok(match &*s {
+            "Function" => RefKind::Function,
+            "Mod" => RefKind::Mod,
+            "Type" => RefKind::Type,
+            "Variable" => RefKind::Variable,
+            _ => RefKind::Unknown(s),
+        })
     }
 }
 
@@ -251,6 +443,8 @@ pub enum ImportKind {
     ExternCrate,
     Use,
     GlobUse,
+    // See `DefKind::Unknown`.
+    Unknown(String),
 }
 
 // Custom impl to read rustc_serialize's format.
@@ -259,12 +453,12 @@ impl Deserialize for ImportKind {
         where D: serde::Deserializer,
     {
         let s = String::deserialize(deserializer)?;
-        match &*s {
-            "ExternCrate" => Ok(ImportKind::ExternCrate),
-            "Use" => Ok(ImportKind::Use),
-            "GlobUse" => Ok(ImportKind::GlobUse),
-            _ => Err(serde::de::Error::custom("unexpected import kind")),
-        }
+        Ok(match &*s {
+            "ExternCrate" => ImportKind::ExternCrate,
+            "Use" => ImportKind::Use,
+            "GlobUse" => ImportKind::GlobUse,
+            _ => ImportKind::Unknown(s),
+        })
     }
 }
 
@@ -282,13 +476,31 @@ pub struct SpanData {
 }
 
 impl BuildResult {
-    fn from_process_output(output: Output, analysis: Vec<Analysis>) -> BuildResult {
-        BuildResult {
-            status: output.status.code(),
-            stdout: String::from_utf8(output.stdout).unwrap(),
-            stderr: String::from_utf8(output.stderr).unwrap(),
-            analysis: analysis,
-        }
+    /// Parses `stderr` into structured diagnostics (see `diagnostics::parse_diagnostics`).
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        diagnostics::parse_diagnostics(&self.stderr)
+    }
+
+    /// Edits that can be applied automatically, derived from any
+    /// machine-applicable suggestions in `self.diagnostics()`.
+    pub fn machine_applicable_edits(&self) -> Vec<Edit> {
+        diagnostics::machine_applicable_edits(&self.diagnostics())
+    }
+
+    /// Builds a span-indexed view of `self.analysis` for go-to-definition
+    /// and type-on-hover queries. See `AnalysisIndex::def_at`.
+    pub fn index(&self) -> AnalysisIndex {
+        AnalysisIndex::new(&self.analysis)
+    }
+
+    /// Computes deglob edits (see `deglob::deglob`) for every `GlobUse`
+    /// import across all analysed crates.
+    pub fn deglob_edits(&self) -> Vec<Edit> {
+        let index = self.index();
+        self.analysis.iter()
+            .flat_map(|a| a.imports.iter().map(move |i| (a, i)))
+            .filter_map(|(a, i)| deglob::deglob(i, a, &index))
+            .collect()
     }
 
     pub fn test_result() -> BuildResult {
@@ -305,6 +517,100 @@ r#"{"message":"use of deprecated item: use raw accessors/constructors in `slice`
 {"message":"unused import, #[warn(unused_imports)] on by default","code":null,"level":"warning","spans":[{"file_name":"src/bin/main.rs","byte_start":108,"byte_end":114,"line_start":4,"line_end":4,"column_start":32,"column_end":38,"text":[{"text":"use xmas_elf::sections::{self, ShType};","highlight_start":32,"highlight_end":38}]}],"children":[]}
 "#.to_owned(),
             analysis: vec![],
+            build_duration: Duration::from_secs(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A save-analysis schema version newer than this code knows about
+    // should degrade to `Unknown`, not fail to deserialize the whole crate.
+    #[test]
+    fn unknown_def_kind_falls_back() {
+        let kind: DefKind = serde_json::from_str("\"SomeFutureKind\"").unwrap();
+        match kind {
+            DefKind::Unknown(s) => assert_eq!(s, "SomeFutureKind"),
+            other => panic!("expected DefKind::Unknown, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_ref_kind_falls_back() {
+        let kind: RefKind = serde_json::from_str("\"SomeFutureKind\"").unwrap();
+        match kind {
+            RefKind::Unknown(s) => assert_eq!(s, "SomeFutureKind"),
+            other => panic!("expected RefKind::Unknown, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_import_kind_falls_back() {
+        let kind: ImportKind = serde_json::from_str("\"SomeFutureKind\"").unwrap();
+        match kind {
+            ImportKind::Unknown(s) => assert_eq!(s, "SomeFutureKind"),
+            other => panic!("expected ImportKind::Unknown, got {:?}", other),
+        }
+    }
+
+    fn test_builder(build_command: &str) -> Builder {
+        Builder {
+            config: Arc::new(Config {
+                build_command: build_command.to_owned(),
+                save_analysis: false,
+            }),
+            analysis_roots: vec![],
+            member_names: vec![],
+        }
+    }
+
+    #[test]
+    fn build_runs_the_command_and_records_duration() {
+        let result = test_builder("echo hi").build().unwrap();
+
+        assert_eq!(result.status, Some(0));
+        assert!(result.stdout.contains("hi"));
+        // A real build takes some time, but not forever; this is a sanity
+        // bound, not a claim about how long `echo` should take.
+        assert!(result.build_duration < Duration::from_secs(5));
+    }
+
+    // Regression test for the deadlock the comment in `run` warns about:
+    // stdout and stderr are drained on their own threads precisely so a
+    // child that fills one pipe while we're blocked reading the other can't
+    // wedge the build. This doesn't reproduce the deadlock directly, but it
+    // does pin down the message order a correct, non-deadlocked run
+    // produces: every `Stdout` line arrives before the final `Done`.
+    #[test]
+    fn build_async_streams_stdout_before_done() {
+        let progress: Vec<_> = test_builder("echo hi").build_async().iter().collect();
+
+        assert!(!progress.is_empty());
+        for p in &progress[..progress.len() - 1] {
+            match *p {
+                BuildProgress::Stdout(_) => {}
+                _ => panic!("expected only Stdout messages before Done"),
+            }
+        }
+        match progress[progress.len() - 1] {
+            BuildProgress::Done(ref result) => assert_eq!(result.status, Some(0)),
+            _ => panic!("expected the last message to be Done"),
+        }
+    }
+
+    #[test]
+    fn build_async_reports_failed_for_a_missing_command() {
+        let progress: Vec<_> = test_builder("this-command-definitely-does-not-exist-rustw")
+            .build_async()
+            .iter()
+            .collect();
+
+        assert_eq!(progress.len(), 1);
+        match progress[0] {
+            BuildProgress::Failed => {}
+            _ => panic!("expected Failed for a command that can't even spawn"),
         }
     }
 }